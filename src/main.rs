@@ -1,21 +1,25 @@
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
-use clap::{arg, Parser};
+use clap::{arg, Parser, ValueEnum};
 use futures::future::join_all;
 use google_cloud_default::WithAuthExt;
-use google_cloud_storage::{
-    client::{Client, ClientConfig},
-    http::{
-        buckets::get::GetBucketRequest,
-        objects::{delete::DeleteObjectRequest, list::ListObjectsRequest, Object},
-    },
-};
+use google_cloud_storage::client::{Client, ClientConfig};
+use google_cloud_token::TokenSource;
 use lazy_static::lazy_static;
 use regex::Regex;
+use serde::Serialize;
+use tokio::sync::Semaphore;
+
+mod store;
+
+use store::{GcsStore, ObjectMeta, ObjectStore, BATCH_LIMIT};
 
 lazy_static! {
     static ref RE: Regex = Regex::new(r"[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}/call-[\w_\-]+/shard-\d{1,5}/(?:script|rc|gcs_delocalization\.sh|gcs_localization\.sh|gcs_transfer\.sh|stdout|stderr|pipelines-logs/action/\d+/(?:stderr|stdout))").unwrap();
+    /// Extracts the `call-<task>` segment so matches can be totalled per task.
+    static ref CALL_RE: Regex = Regex::new(r"call-[\w_\-]+").unwrap();
 }
 
 #[derive(Parser, Debug)]
@@ -28,66 +32,148 @@ struct Args {
     bucket: String,
     #[arg(long, action, help = "Dry run, don't actually delete any files")]
     dry_run: bool,
+    #[arg(
+        long,
+        action,
+        help = "Delete every generation of matching objects, not just the live version"
+    )]
+    all_versions: bool,
+    #[arg(
+        long,
+        short = 'c',
+        default_value_t = 16,
+        help = "Maximum number of concurrent listing and deletion requests in flight"
+    )]
+    concurrency: usize,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Format::Table,
+        help = "Output format for the run summary"
+    )]
+    format: Format,
+}
+
+/// How the run summary is rendered.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// Human-readable table on stdout (the default).
+    Table,
+    /// Structured JSON (match list + summary) on stdout, for other tooling.
+    Json,
 }
 
-async fn get_client() -> Result<Client> {
+/// Build an authenticated GCS client alongside the token source backing it, so
+/// that raw batch requests (which the typed client does not expose) can reuse
+/// the same credentials.
+async fn get_client() -> Result<(Client, Arc<dyn TokenSource>)> {
     let config = ClientConfig::default().with_auth().await?;
-    Ok(Client::new(config))
+    let token_source = config.token_source_provider.token_source();
+    Ok((Client::new(config), token_source))
+}
+
+/// The cloud backend a path points at, determined by its URL scheme.
+#[derive(Debug, PartialEq, Eq)]
+enum Scheme {
+    Gcs,
+    S3,
+    Azure,
 }
 
 #[derive(Debug)]
 struct GsPath {
+    scheme: Scheme,
     bucket: String,
     folder: String,
 }
 
-/// Given a string formatted as a gs:// path (e.g.
-/// gs://my-bucket/my_folder/my_obj.txt), return the bucket, folder, and object
+/// Given a string formatted as a cloud-storage path (e.g.
+/// gs://my-bucket/my_folder/my_obj.txt), return the backend scheme, bucket, and
+/// folder. The scheme selects which [`ObjectStore`] implementation is used so
+/// the same cleaner can run against `gs://`, `s3://`, and `az://` trees.
 fn parse_gsutil_path(path: &str) -> Result<GsPath> {
-    if !path.starts_with("gs://") {
-        return Err(anyhow!("Invalid gsutil path format, no gs:// prefix"));
-    }
-    // strip off the gs:// prefix
-    let path_without_gs = path.strip_prefix("gs://").unwrap();
+    let (scheme, rest) = if let Some(rest) = path.strip_prefix("gs://") {
+        (Scheme::Gcs, rest)
+    } else if let Some(rest) = path.strip_prefix("s3://") {
+        (Scheme::S3, rest)
+    } else if let Some(rest) = path.strip_prefix("az://") {
+        (Scheme::Azure, rest)
+    } else {
+        return Err(anyhow!(
+            "Invalid path format, expected a gs://, s3://, or az:// prefix"
+        ));
+    };
+
     // split the path into bucket and folder at the first /
-    let parts: Vec<&str> = path_without_gs.splitn(2, '/').collect();
-    // if there are less than 2 parts, then there is no folder specified
-    if parts.len() < 2 {
-        return Err(anyhow!("Invalid gsutil path format, no folder specified"));
+    let parts: Vec<&str> = rest.splitn(2, '/').collect();
+    let bucket = parts[0];
+    if bucket.is_empty() {
+        return Err(anyhow!("Invalid path format, no bucket specified"));
     }
+    // A missing or empty folder (`gs://bucket` or `gs://bucket/`) means the
+    // whole bucket: list it with an empty prefix rather than rejecting it.
+    let folder = parts.get(1).copied().unwrap_or_default();
 
     Ok(GsPath {
-        bucket: parts[0].to_string(),
-        folder: parts[1].to_string(),
+        scheme,
+        bucket: bucket.to_string(),
+        folder: folder.to_string(),
     })
 }
 
-async fn remove_objects(client: Arc<Client>, items: Vec<Object>) -> Result<()> {
-    let mut futures = Vec::with_capacity(items.len());
-    for item in items {
-        let cloned_client = Arc::clone(&client);
-        futures.push(tokio::spawn(async move {
-            if let Err(e) = cloned_client
-                .delete_object(&DeleteObjectRequest {
-                    bucket: item.bucket,
-                    object: item.name,
-                    ..Default::default()
-                })
-                .await
-            {
-                eprintln!("Error deleting object: {}", e);
-            };
-        }));
+/// Construct the [`ObjectStore`] implementation for the parsed path's scheme.
+/// Only GCS is wired up today; the other schemes parse successfully so support
+/// is a matter of adding an implementation, not touching the pipeline.
+async fn build_store(path: &GsPath, all_versions: bool) -> Result<Arc<dyn ObjectStore>> {
+    match path.scheme {
+        Scheme::Gcs => {
+            let (client, token_source) = get_client().await?;
+            let http = Arc::new(reqwest::Client::new());
+            Ok(Arc::new(GcsStore::new(
+                Arc::new(client),
+                http,
+                token_source,
+                path.bucket.clone(),
+                all_versions,
+            )))
+        }
+        Scheme::S3 => Err(anyhow!("s3:// backend is not yet implemented")),
+        Scheme::Azure => Err(anyhow!("az:// backend is not yet implemented")),
     }
+}
+
+/// Delete every object in `items` by packing them into batches of up to
+/// [`BATCH_LIMIT`]. Each batch request acquires a permit from the shared
+/// `semaphore` so that no more than `--concurrency` requests are ever in
+/// flight across the whole program.
+async fn remove_objects(
+    store: Arc<dyn ObjectStore>,
+    semaphore: Arc<Semaphore>,
+    items: Vec<ObjectMeta>,
+) -> Result<()> {
+    let chunks = items.chunks(BATCH_LIMIT).map(<[_]>::to_vec);
+
+    let handles = chunks.map(|chunk| {
+        let store = Arc::clone(&store);
+        let semaphore = Arc::clone(&semaphore);
+        tokio::spawn(async move {
+            // Acquiring here (rather than inside the spawn of every list page)
+            // means listing and deleting share the same backpressure budget.
+            let _permit = semaphore.acquire().await.expect("semaphore not closed");
+            if let Err(e) = store.delete(&chunk).await {
+                eprintln!("Error sending batch delete request: {e}");
+            }
+        })
+    });
 
-    join_all(futures).await;
+    join_all(handles).await;
 
     Ok(())
 }
 
 /// Iterate over each object in the bucket and print the ones that match our
 /// regex
-async fn filter_objects(items: Vec<Object>) -> Vec<Object> {
+async fn filter_objects(items: Vec<ObjectMeta>) -> Vec<ObjectMeta> {
     items
         .into_iter()
         // Filter out any objects that don't match our regex
@@ -101,83 +187,184 @@ async fn filter_objects(items: Vec<Object>) -> Vec<Object> {
         .collect()
 }
 
-async fn handle_removal(
-    items: Option<Vec<Object>>,
-    client: Arc<Client>,
-    dry_run: bool,
-) -> Result<()> {
-    if let Some(items) = items {
-        let filtered_objects = filter_objects(items).await;
-        if dry_run {
-            for obj in filtered_objects {
-                eprintln!("gs://{}/{}", obj.bucket, obj.name);
+/// Per-`call-*` task rollup of how many objects matched and their total size.
+#[derive(Debug, Serialize)]
+struct CallBreakdown {
+    task: String,
+    objects: u64,
+    bytes: i64,
+}
+
+/// Totals across every matched object, plus a per-task breakdown.
+#[derive(Debug, Serialize)]
+struct Summary {
+    total_objects: u64,
+    total_bytes: i64,
+    per_call: Vec<CallBreakdown>,
+}
+
+impl Summary {
+    /// Accumulate a summary from the full set of matched objects.
+    fn from_matches(matches: &[ObjectMeta]) -> Self {
+        let mut per_call: BTreeMap<String, (u64, i64)> = BTreeMap::new();
+        let mut total_bytes = 0;
+        for obj in matches {
+            total_bytes += obj.size;
+            let task = CALL_RE
+                .find(&obj.name)
+                .map_or_else(|| "unknown".to_string(), |m| m.as_str().to_string());
+            let entry = per_call.entry(task).or_default();
+            entry.0 += 1;
+            entry.1 += obj.size;
+        }
+
+        let per_call = per_call
+            .into_iter()
+            .map(|(task, (objects, bytes))| CallBreakdown {
+                task,
+                objects,
+                bytes,
+            })
+            .collect();
+
+        Summary {
+            total_objects: matches.len() as u64,
+            total_bytes,
+            per_call,
+        }
+    }
+}
+
+/// The full machine-readable report: every matched object and the summary.
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    matches: &'a [ObjectMeta],
+    summary: &'a Summary,
+}
+
+/// Render a byte count in human-friendly units (KiB/MiB/...).
+fn human_bytes(bytes: i64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} {}", UNITS[unit])
+    } else {
+        format!("{value:.2} {}", UNITS[unit])
+    }
+}
+
+/// Print the run summary in the requested format.
+fn print_summary(matches: &[ObjectMeta], summary: &Summary, format: Format) -> Result<()> {
+    match format {
+        Format::Json => {
+            let report = Report { matches, summary };
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        Format::Table => {
+            println!("Matched {} objects by task:", summary.total_objects);
+            for call in &summary.per_call {
+                println!(
+                    "  {:<30} {:>8} objects  {:>12}",
+                    call.task,
+                    call.objects,
+                    human_bytes(call.bytes)
+                );
             }
-        } else {
-            remove_objects(client, filtered_objects).await?;
+            println!(
+                "Total: {} objects, {} reclaimable",
+                summary.total_objects,
+                human_bytes(summary.total_bytes)
+            );
         }
     }
     Ok(())
 }
 
+/// Filter a list page, delete the matches unless this is a dry run, and return
+/// the matches so the caller can build the run-wide summary.
+async fn handle_removal(
+    items: Vec<ObjectMeta>,
+    store: Arc<dyn ObjectStore>,
+    semaphore: Arc<Semaphore>,
+    dry_run: bool,
+) -> Result<Vec<ObjectMeta>> {
+    let filtered_objects = filter_objects(items).await;
+    if !dry_run {
+        remove_objects(store, semaphore, filtered_objects.clone()).await?;
+    }
+    Ok(filtered_objects)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args: Args = Args::parse();
-    let client = Arc::new(get_client().await?);
 
     let gs_path = parse_gsutil_path(&args.bucket)?;
+    let store = build_store(&gs_path, args.all_versions).await?;
+
+    // A single semaphore shared across every listing and deletion task bounds
+    // the number of concurrent requests to `--concurrency`.
+    let semaphore = Arc::new(Semaphore::new(args.concurrency));
+    // Keep every spawned handle so we can await them before returning; a
+    // detached task would otherwise be dropped when `main` exits, killing
+    // in-flight deletes.
+    let mut handles = Vec::new();
 
     eprintln!("Listing objects in bucket: {gs_path:?}");
 
     // verify that the bucket exists
-    client
-        .get_bucket(&GetBucketRequest {
-            bucket: gs_path.bucket.clone(),
-            ..Default::default()
-        })
-        .await?;
+    store.verify_bucket().await?;
 
     // do our first request
-    let mut res = client
-        .list_objects(&ListObjectsRequest {
-            bucket: gs_path.bucket.to_string(),
-            prefix: Some(gs_path.folder.to_string()),
-            ..Default::default()
-        })
-        .await?;
+    let mut page = {
+        let _permit = semaphore.acquire().await?;
+        store.list(&gs_path.folder, None).await?
+    };
+    loop {
+        // spawn a thread to handle this list response
+        handles.push(tokio::spawn(handle_removal(
+            page.items,
+            Arc::clone(&store),
+            Arc::clone(&semaphore),
+            args.dry_run,
+        )));
 
-    if args.dry_run {
-        println!("Would delete the following objects:");
+        match page.next_page_token {
+            // do our next request
+            Some(token) => {
+                let _permit = semaphore.acquire().await?;
+                page = store.list(&gs_path.folder, Some(token)).await?;
+            }
+            None => break,
+        }
     }
-    // spawn a thread to handle this response
-    tokio::spawn(handle_removal(res.items, Arc::clone(&client), args.dry_run));
-
-    while let Some(ref page) = res.next_page_token {
-        // do our next request
-        res = client
-            .list_objects(&ListObjectsRequest {
-                bucket: gs_path.bucket.to_string(),
-                prefix: Some(gs_path.folder.to_string()),
-                page_token: Some(page.to_string()),
-                ..Default::default()
-            })
-            .await?;
-        // spawn a thread to handle this list response
-        tokio::spawn(handle_removal(
-            res.items,
-            Arc::clone(&client),
-            args.dry_run.clone(),
-        ));
+
+    // Wait for every deletion task to finish before exiting, otherwise the
+    // process can return while deletes are still pending, and gather the
+    // matched objects from every page for the summary.
+    let mut matches = Vec::new();
+    for handle in join_all(handles).await {
+        match handle {
+            Ok(Ok(page_matches)) => matches.extend(page_matches),
+            Ok(Err(e)) => eprintln!("Error handling list page: {e}"),
+            Err(e) => eprintln!("Deletion task panicked: {e}"),
+        }
     }
 
+    print_summary(&matches, &Summary::from_matches(&matches), args.format)?;
+
     Ok(())
 }
 
 // create a module for tests
 #[cfg(test)]
 mod test {
-    use google_cloud_storage::http::objects::Object;
-
-    use super::{filter_objects, parse_gsutil_path};
+    use super::{filter_objects, parse_gsutil_path, ObjectMeta};
 
     #[test]
     fn test_parse_gsutil_path() {
@@ -202,25 +389,33 @@ mod test {
     }
 
     #[test]
-    #[should_panic]
-    fn test_parse_gsutil_path_no_folder() {
-        // test parse_gsutil_path
+    fn test_parse_gsutil_path_whole_bucket() {
+        // a bare bucket, a trailing slash, and a folder should all parse, with
+        // the first two resolving to an empty (whole-bucket) prefix
         let gs_path = parse_gsutil_path("gs://my-bucket").unwrap();
         assert_eq!(gs_path.bucket, "my-bucket");
-        assert_eq!(gs_path.folder, "my_folder/my_obj.txt");
+        assert_eq!(gs_path.folder, "");
+
+        let gs_path = parse_gsutil_path("gs://my-bucket/").unwrap();
+        assert_eq!(gs_path.bucket, "my-bucket");
+        assert_eq!(gs_path.folder, "");
+
+        let gs_path = parse_gsutil_path("gs://my-bucket/workflows/").unwrap();
+        assert_eq!(gs_path.bucket, "my-bucket");
+        assert_eq!(gs_path.folder, "workflows/");
     }
 
     #[tokio::test]
     async fn test_filter_objects() {
         // test the list_objects function
         let items = vec![
-            Object {
+            ObjectMeta {
                 bucket: "my-bucket".to_string(),
                 name: "my_folder/b189154b-fd26-4ed1-a6f0-4f6191f1e820/call-foobar/shard-42/script"
                     .to_string(),
                 ..Default::default()
             },
-            Object {
+            ObjectMeta {
                 bucket: "my-bucket".to_string(),
                 name: "my_folder/b189154b-fd26-4ed1-a6f0-4f6191f1e820/call-foobar/shard-42/\
                        my_fake_other_file.bam"
@@ -238,12 +433,12 @@ mod test {
         );
 
         let items = vec![
-            Object {
+            ObjectMeta {
                 bucket: "my-bucket".to_string(),
                 name: "my_folder/call-foobar/shard-42/script".to_string(),
                 ..Default::default()
             },
-            Object {
+            ObjectMeta {
                 bucket: "my-bucket".to_string(),
                 name: "my_folder/call-foobar/shard-42/my_fake_other_file.bam".to_string(),
                 ..Default::default()
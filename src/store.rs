@@ -0,0 +1,239 @@
+//! Storage-backend abstraction.
+//!
+//! Cromwell writes execution directories to more than one cloud — GCS today,
+//! but also AWS S3 and Azure Blob. The regex-driven paging/filter/delete
+//! pipeline is identical regardless of backend, so the backend-specific parts
+//! (listing a prefix with pagination and deleting objects) live behind the
+//! [`ObjectStore`] trait, modeled on the `ObjectStore` abstraction in
+//! arrow-rs's `object_store` crate. The concrete backend is chosen from the
+//! URL scheme in [`crate::parse_gsutil_path`].
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use google_cloud_storage::{
+    client::Client,
+    http::{
+        buckets::get::GetBucketRequest,
+        objects::list::ListObjectsRequest,
+    },
+};
+use google_cloud_token::TokenSource;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+/// The GCS JSON API batch endpoint. Up to 100 sub-requests may be packed into a
+/// single `multipart/mixed` POST against this URL.
+const BATCH_ENDPOINT: &str = "https://storage.googleapis.com/batch/storage/v1";
+/// Maximum number of sub-requests the batch endpoint accepts in one call.
+pub const BATCH_LIMIT: usize = 100;
+/// The multipart boundary separating sub-requests in a batch body.
+const BATCH_BOUNDARY: &str = "gcs_cromwell_cleaner_batch_boundary";
+
+/// A single stored object, normalized across backends.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ObjectMeta {
+    /// The bucket (GCS), bucket (S3), or container (Azure) holding the object.
+    pub bucket: String,
+    /// The object's full name/key, relative to the bucket.
+    pub name: String,
+    /// The object's generation. On versioned buckets a single name can have
+    /// several generations; the live one is targeted by omitting this.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub generation: i64,
+    /// The object's size in bytes, used to estimate reclaimed storage.
+    pub size: i64,
+    /// The object's storage class (e.g. `STANDARD`, `NEARLINE`).
+    pub storage_class: String,
+    /// When the object was created, if the backend reports it.
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub time_created: Option<OffsetDateTime>,
+}
+
+fn is_zero(generation: &i64) -> bool {
+    *generation == 0
+}
+
+/// One page of a listing: the objects on the page plus the token, if any, that
+/// fetches the next page.
+pub struct ObjectPage {
+    pub items: Vec<ObjectMeta>,
+    pub next_page_token: Option<String>,
+}
+
+/// The subset of object-store behavior this cleaner relies on: paginated
+/// listing under a prefix, and deletion of a set of objects.
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    /// Confirm the backing bucket/container exists before any listing starts.
+    async fn verify_bucket(&self) -> Result<()>;
+
+    /// List one page of objects under `prefix`. Pass the previous page's
+    /// [`ObjectPage::next_page_token`] to continue paging; pass `None` for the
+    /// first page.
+    async fn list(&self, prefix: &str, page_token: Option<String>) -> Result<ObjectPage>;
+
+    /// Delete a batch of objects. Implementations are free to coalesce the
+    /// deletes into a single backend request; callers must pass at most
+    /// [`BATCH_LIMIT`] objects per call.
+    async fn delete(&self, objects: &[ObjectMeta]) -> Result<()>;
+}
+
+/// GCS-backed [`ObjectStore`]. Listing uses the typed
+/// [`google_cloud_storage::client::Client`]; deletion uses the raw JSON batch
+/// endpoint (which the typed client does not expose) authenticated with the
+/// same token source.
+pub struct GcsStore {
+    client: Arc<Client>,
+    http: Arc<reqwest::Client>,
+    token_source: Arc<dyn TokenSource>,
+    bucket: String,
+    /// When set, list every generation and delete each one rather than only
+    /// the live object.
+    all_versions: bool,
+}
+
+impl GcsStore {
+    pub fn new(
+        client: Arc<Client>,
+        http: Arc<reqwest::Client>,
+        token_source: Arc<dyn TokenSource>,
+        bucket: String,
+        all_versions: bool,
+    ) -> Self {
+        Self {
+            client,
+            http,
+            token_source,
+            bucket,
+            all_versions,
+        }
+    }
+
+    /// Serialize a single object deletion as an embedded HTTP request for the
+    /// batch body. The object name is URL-encoded so names containing `/` or
+    /// other reserved characters resolve to the correct resource. When
+    /// `all_versions` is set, the object's generation is pinned so the exact
+    /// (possibly noncurrent) version is removed; otherwise the live version is
+    /// deleted.
+    fn encode_delete_request(&self, index: usize, object: &ObjectMeta) -> String {
+        let encoded_name = utf8_percent_encode(&object.name, NON_ALPHANUMERIC);
+        let generation = if self.all_versions {
+            format!("?generation={}", object.generation)
+        } else {
+            String::new()
+        };
+        format!(
+            "--{boundary}\r\n\
+             Content-Type: application/http\r\n\
+             Content-ID: <item-{index}>\r\n\
+             \r\n\
+             DELETE /storage/v1/b/{bucket}/o/{name}{generation} HTTP/1.1\r\n\
+             \r\n",
+            boundary = BATCH_BOUNDARY,
+            index = index,
+            bucket = object.bucket,
+            name = encoded_name,
+            generation = generation,
+        )
+    }
+
+    /// Scan a batch response body for the sub-request status lines and report
+    /// any failure that is not a `404` (which means the object was already
+    /// gone).
+    fn report_batch_statuses(response: &str, objects: &[ObjectMeta]) {
+        // Status lines appear in the same order as the sub-requests we sent,
+        // one `HTTP/1.1 <code> <reason>` line per embedded response.
+        let statuses = response
+            .lines()
+            .filter_map(|line| line.strip_prefix("HTTP/1.1 "))
+            .filter_map(|rest| rest.split_whitespace().next())
+            .filter_map(|code| code.parse::<u16>().ok());
+
+        for (status, object) in statuses.zip(objects) {
+            match status {
+                200 | 204 | 404 => {}
+                other => eprintln!(
+                    "Error deleting object gs://{}/{}: HTTP {other}",
+                    object.bucket, object.name
+                ),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for GcsStore {
+    async fn verify_bucket(&self) -> Result<()> {
+        self.client
+            .get_bucket(&GetBucketRequest {
+                bucket: self.bucket.clone(),
+                ..Default::default()
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str, page_token: Option<String>) -> Result<ObjectPage> {
+        let res = self
+            .client
+            .list_objects(&ListObjectsRequest {
+                bucket: self.bucket.clone(),
+                prefix: Some(prefix.to_string()),
+                page_token,
+                // Surface every generation so noncurrent versions can be
+                // purged; the live-only default leaves this unset.
+                versions: self.all_versions.then_some(true),
+                ..Default::default()
+            })
+            .await?;
+
+        let items = res
+            .items
+            .unwrap_or_default()
+            .into_iter()
+            .map(|obj| ObjectMeta {
+                bucket: obj.bucket,
+                name: obj.name,
+                generation: obj.generation,
+                size: obj.size,
+                storage_class: obj.storage_class,
+                time_created: obj.time_created,
+            })
+            .collect();
+
+        Ok(ObjectPage {
+            items,
+            next_page_token: res.next_page_token,
+        })
+    }
+
+    async fn delete(&self, objects: &[ObjectMeta]) -> Result<()> {
+        let mut body = String::new();
+        for (index, object) in objects.iter().enumerate() {
+            body.push_str(&self.encode_delete_request(index, object));
+        }
+        body.push_str(&format!("--{BATCH_BOUNDARY}--\r\n"));
+
+        let token = self.token_source.token().await.map_err(|e| anyhow!(e))?;
+        let response = self
+            .http
+            .post(BATCH_ENDPOINT)
+            .header("Authorization", token)
+            .header(
+                "Content-Type",
+                format!("multipart/mixed; boundary={BATCH_BOUNDARY}"),
+            )
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let text = response.text().await?;
+        Self::report_batch_statuses(&text, objects);
+
+        Ok(())
+    }
+}